@@ -50,3 +50,50 @@ if __name__ == "__main__":
         print(results.shape)  # (24328694, 3), each row is [point_index_i, point_index_j, distance]
 
     query_pairs()
+
+    def parallel_matches_serial():
+        # parallel results come back in exactly the serial order, so no sort is needed
+        for parallel_result, serial_result in [
+            (
+                tree.within_unsorted(distance=0.05, query_points=query_points, parallel=True),
+                tree.within_unsorted(distance=0.05, query_points=query_points, parallel=False),
+            ),
+            (
+                tree.query_pairs(distance=0.05, parallel=True),
+                tree.query_pairs(distance=0.05, parallel=False),
+            ),
+        ]:
+            assert np.array_equal(parallel_result, serial_result)
+
+    parallel_matches_serial()
+
+    def metrics_share_one_tree():
+        # the metric is picked per query; the same tree answers both without a rebuild
+        sample = query_points[:200]
+        for metric, brute_force in [
+            ("euclidean", np.sqrt(((sample[:, None, :] - points[None, :, :]) ** 2).sum(-1))),
+            ("manhattan", np.abs(sample[:, None, :] - points[None, :, :]).sum(-1)),
+        ]:
+            distances = tree.nearest_n_distances(5, sample, metric=metric)
+            assert np.allclose(distances, np.sort(brute_force, axis=1)[:, :5], rtol=1e-5)
+
+    metrics_share_one_tree()
+
+    def indices_are_input_rows():
+        # result indices are row positions in `points`; original_index is the identity
+        nearest = tree.nearest_one(points)
+        assert np.array_equal(nearest["index"], np.arange(len(points)))
+        assert all(tree.original_index(i) == i for i in (0, 1, len(points) - 1))
+
+    indices_are_input_rows()
+
+    def cache_serves_read_only_results():
+        # an exact repeat is answered from the cache with the same read-only result
+        cached_tree = kiddo_py.PyKdTree(dimensions=dim, points=points, cache_size=4)
+        first = cached_tree.nearest_n(3, query_points)
+        assert not first.flags.writeable
+        repeat = cached_tree.nearest_n(3, query_points)
+        assert repeat is first
+        assert np.array_equal(repeat, tree.nearest_n(3, query_points))
+
+    cache_serves_read_only_results()